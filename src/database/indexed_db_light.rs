@@ -1,4 +1,5 @@
-//! Persistent storage for light-client data based on IndexedDB, the in-brower database.
+//! Persistent storage for light-client data, backed by IndexedDB when available in the browser
+//! environment, and falling back to a non-persistent in-memory store otherwise.
 
 // TODO: obviously very work-in-progress
 
@@ -7,26 +8,294 @@
 
 use crate::header;
 
-use futures::channel::oneshot;
+use futures::{
+    channel::{mpsc, oneshot},
+    stream, Stream,
+};
 use js_sys::{Array, ArrayBuffer, Uint8Array};
+use parity_scale_codec::{Decode, Encode};
+use std::{
+    cell::{Cell, RefCell},
+    collections::BTreeMap,
+    iter,
+    marker::PhantomData,
+    pin::Pin,
+};
 use wasm_bindgen::{prelude::*, JsCast as _};
-use web_sys::{DomException, Event, IdbDatabase, IdbTransaction, IdbTransactionMode};
+use web_sys::{
+    DomException, Event, IdbCursorDirection, IdbCursorWithValue, IdbDatabase, IdbKeyRange,
+    IdbTransaction, IdbTransactionMode, WorkerGlobalScope,
+};
 
 /// An open database.
+///
+/// Uses IndexedDB when available, and transparently falls back to a non-persistent in-memory
+/// store otherwise (IndexedDB is for example unavailable in Firefox's private mode). Use
+/// [`Database::backend`] to find out which backend ended up being used.
 pub struct Database {
-    inner: send_wrapper::SendWrapper<IdbDatabase>,
+    backend: Backend,
+    /// Set through [`Database::set_retention`]. When set, every successful [`Database::apply`]
+    /// (and thus [`Database::insert_header`]/[`Database::insert_headers`]) prunes headers older
+    /// than this many blocks behind the best head.
+    retention: Cell<Option<u64>>,
+}
+
+enum Backend {
+    IndexedDb(IndexedDb),
+    Memory(Memory),
+}
+
+/// Which backend a [`Database`] is actually using. See [`Database::backend`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BackendKind {
+    IndexedDb,
+    Memory,
+}
+
+/// A single write operation, to be applied atomically alongside others through
+/// [`Database::apply`].
+pub enum WriteOp<'a> {
+    /// Inserts a SCALE-encoded header as part of the best chain, as if
+    /// [`Database::insert_header`] had been called.
+    InsertHeader(&'a [u8]),
+    /// Deletes the entry at the given key in the given object store, which may be any store
+    /// declared through the [`Schema`] the database was opened with, not just the three built-in
+    /// ones.
+    ///
+    /// `key` is the raw 32-byte block hash for `"block-headers"`, the raw `meta` key bytes for
+    /// `"meta"`, and an 8-byte little-endian block number for `"best-chain"` (whose real key
+    /// type is a plain number, not a byte string; both backends re-encode it accordingly). For
+    /// any other store, `key` is the raw, already-encoded key bytes, used as-is.
+    Delete { column: &'static str, key: Vec<u8> },
+}
+
+/// The result of applying a single [`WriteOp`], returned by [`Database::apply`] in the same
+/// order as the submitted ops.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApplyOutcome {
+    /// The operation was applied as requested: a [`WriteOp::Delete`], or a
+    /// [`WriteOp::InsertHeader`] that became the new best head.
+    Applied,
+    /// The operation had no effect. For a [`WriteOp::InsertHeader`], this means the header
+    /// didn't extend or outweigh the current best head, or its branch couldn't be proven to
+    /// connect to the existing chain without crossing the finalized head; the header is still
+    /// stored in `block-headers` regardless (unless it was already known). For a
+    /// [`WriteOp::Delete`], this means `key` was malformed for `column` and nothing was deleted.
+    Skipped,
 }
 
 impl Database {
-    /// Tries to open the database from the browser environment.
-    pub async fn open(db_name: &str) -> Result<Self, OpenError> {
-        // TODO: also allow `WorkerGlobalScope`
-        let window = web_sys::window().ok_or(OpenError::NoWindow)?;
-        let idb_factory = window
-            .indexed_db()
-            .map_err(OpenError::IndexedDbNotSupported)?
+    /// Tries to open the database from the browser environment, migrating it to `schema` if
+    /// necessary, and transparently falling back to a non-persistent in-memory store if
+    /// IndexedDB is unavailable.
+    pub async fn open(db_name: &str, schema: &Schema) -> Self {
+        let backend = match IndexedDb::open(db_name, schema).await {
+            Ok(db) => Backend::IndexedDb(db),
+            Err(_) => Backend::Memory(Memory::new()),
+        };
+        Database {
+            backend,
+            retention: Cell::new(None),
+        }
+    }
+
+    /// Configures automatic pruning: after every successful [`Database::apply`] (and thus
+    /// [`Database::insert_header`]/[`Database::insert_headers`]), headers more than
+    /// `keep_last` blocks behind the best head are deleted, down to (but not below) the
+    /// finalized head. Pass `None` to disable automatic pruning (the default); see
+    /// [`Database::prune`] to prune on demand instead.
+    pub fn set_retention(&self, keep_last: Option<u64>) {
+        self.retention.set(keep_last);
+    }
+
+    /// Deletes every header more than `keep_last` blocks behind the current best head, down to
+    /// (but not below) the finalized head.
+    pub async fn prune(&self, keep_last: u64) -> Result<(), AccessError> {
+        match &self.backend {
+            Backend::IndexedDb(db) => db.prune(keep_last).await,
+            Backend::Memory(db) => db.prune(keep_last).await,
+        }
+    }
+
+    async fn maybe_prune(&self) -> Result<(), AccessError> {
+        if let Some(keep_last) = self.retention.get() {
+            self.prune(keep_last).await?;
+        }
+        Ok(())
+    }
+
+    /// Which backend this database is actually using.
+    pub fn backend(&self) -> BackendKind {
+        match &self.backend {
+            Backend::IndexedDb(_) => BackendKind::IndexedDb,
+            Backend::Memory(_) => BackendKind::Memory,
+        }
+    }
+
+    /// Returns the concrete IndexedDB backend, if that's the one in use.
+    ///
+    /// Use this to access IndexedDB-specific functionality that has no [`Memory`] equivalent.
+    pub fn as_indexed_db(&self) -> Option<&IndexedDb> {
+        match &self.backend {
+            Backend::IndexedDb(db) => Some(db),
+            Backend::Memory(_) => None,
+        }
+    }
+
+    /// Returns a strongly-typed view over the given object store, which must be part of the
+    /// [`Schema`] the database was opened with. Works the same way regardless of which
+    /// [`BackendKind`] ended up being used.
+    ///
+    /// Keys and values are serialized using SCALE, through the [`Encode`]/[`Decode`] traits.
+    pub fn store<K: Encode, V: Encode + Decode>(&self, object_store: &'static str) -> Store<'_, K, V> {
+        match &self.backend {
+            Backend::IndexedDb(db) => db.store(object_store),
+            Backend::Memory(db) => db.store(object_store),
+        }
+    }
+
+    /// Inserts the given header in the database. Returns [`ApplyOutcome::Applied`] if it became
+    /// the new best head, or [`ApplyOutcome::Skipped`] if it didn't (it may still have been
+    /// stored, just not as part of the canonical chain).
+    pub async fn insert_header(
+        &self,
+        scale_encoded_header: &[u8],
+    ) -> Result<ApplyOutcome, AccessError> {
+        Ok(self
+            .insert_headers(iter::once(scale_encoded_header))
+            .await?
+            .into_iter()
+            .next()
+            .unwrap())
+    }
+
+    /// Inserts the given headers in the database, all within a single atomic operation. The
+    /// returned [`ApplyOutcome`]s are in the same order as `headers`.
+    pub async fn insert_headers<'a>(
+        &self,
+        headers: impl IntoIterator<Item = &'a [u8]>,
+    ) -> Result<Vec<ApplyOutcome>, AccessError> {
+        self.apply(headers.into_iter().map(WriteOp::InsertHeader))
+            .await
+    }
+
+    /// Atomically applies a batch of [`WriteOp`]s. The returned [`ApplyOutcome`]s are in the same
+    /// order as `ops`.
+    pub async fn apply<'a>(
+        &self,
+        ops: impl IntoIterator<Item = WriteOp<'a>>,
+    ) -> Result<Vec<ApplyOutcome>, AccessError> {
+        let outcomes = match &self.backend {
+            Backend::IndexedDb(db) => db.apply(ops).await,
+            Backend::Memory(db) => db.apply(ops).await,
+        }?;
+        self.maybe_prune().await?;
+        Ok(outcomes)
+    }
+
+    /// Streams the `(block number, block hash)` pairs of the best chain whose number is within
+    /// the given bounds, in increasing order.
+    pub fn iter_best_chain(
+        &self,
+        from: u64,
+        to: u64,
+    ) -> Pin<Box<dyn Stream<Item = (u64, [u8; 32])> + Unpin + '_>> {
+        match &self.backend {
+            Backend::IndexedDb(db) => Box::pin(db.iter_best_chain(from, to)),
+            Backend::Memory(db) => Box::pin(db.iter_best_chain(from, to)),
+        }
+    }
+
+    /// Returns the hash and number of the current best block, if any header has been inserted.
+    pub async fn best_head(&self) -> Result<Option<(u64, [u8; 32])>, AccessError> {
+        match &self.backend {
+            Backend::IndexedDb(db) => db.best_head().await,
+            Backend::Memory(db) => db.best_head().await,
+        }
+    }
+
+    /// Returns the hash and number of the current finalized block, if any has been set through
+    /// [`Database::set_finalized`].
+    pub async fn finalized_head(&self) -> Result<Option<(u64, [u8; 32])>, AccessError> {
+        match &self.backend {
+            Backend::IndexedDb(db) => db.finalized_head().await,
+            Backend::Memory(db) => db.finalized_head().await,
+        }
+    }
+
+    /// Sets the finalized block. The light client must never reorg the canonical chain below
+    /// this block.
+    pub async fn set_finalized(&self, number: u64, hash: [u8; 32]) -> Result<(), AccessError> {
+        match &self.backend {
+            Backend::IndexedDb(db) => db.set_finalized(number, hash).await,
+            Backend::Memory(db) => db.set_finalized(number, hash).await,
+        }
+    }
+}
+
+/// Operations common to every storage backend, implemented by [`IndexedDb`] and [`Memory`].
+///
+/// [`Database`] doesn't use this trait for dispatch (it matches over its backend directly, as
+/// the methods below take `impl Trait` parameters and thus aren't object-safe), but it documents
+/// the surface that a new backend is expected to provide.
+pub trait Storage {
+    /// Inserts the given header in the database.
+    async fn insert_header(
+        &self,
+        scale_encoded_header: &[u8],
+    ) -> Result<ApplyOutcome, AccessError>;
+
+    /// Inserts the given headers in the database, all within a single atomic operation.
+    async fn insert_headers<'a>(
+        &self,
+        headers: impl IntoIterator<Item = &'a [u8]>,
+    ) -> Result<Vec<ApplyOutcome>, AccessError>;
+
+    /// Atomically applies a batch of [`WriteOp`]s.
+    async fn apply<'a>(
+        &self,
+        ops: impl IntoIterator<Item = WriteOp<'a>>,
+    ) -> Result<Vec<ApplyOutcome>, AccessError>;
+
+    /// Streams the `(block number, block hash)` pairs of the best chain whose number is within
+    /// the given bounds, in increasing order.
+    fn iter_best_chain(&self, from: u64, to: u64) -> impl Stream<Item = (u64, [u8; 32])> + Unpin;
+
+    /// Returns the hash and number of the current best block, if any header has been inserted.
+    async fn best_head(&self) -> Result<Option<(u64, [u8; 32])>, AccessError>;
+
+    /// Returns the hash and number of the current finalized block, if any has been set.
+    async fn finalized_head(&self) -> Result<Option<(u64, [u8; 32])>, AccessError>;
+
+    /// Sets the finalized block.
+    async fn set_finalized(&self, number: u64, hash: [u8; 32]) -> Result<(), AccessError>;
+
+    /// Deletes every header more than `keep_last` blocks behind the current best head, down to
+    /// (but not below) the finalized head.
+    async fn prune(&self, keep_last: u64) -> Result<(), AccessError>;
+}
+
+/// Storage backend based on IndexedDB, the in-browser database.
+pub struct IndexedDb {
+    inner: send_wrapper::SendWrapper<IdbDatabase>,
+}
+
+impl IndexedDb {
+    /// Tries to open the database from the browser environment, migrating it to `schema` if
+    /// necessary.
+    pub async fn open(db_name: &str, schema: &Schema) -> Result<Self, OpenError> {
+        let idb_factory = if let Some(window) = web_sys::window() {
+            window.indexed_db()
+        } else if let Ok(worker) = js_sys::global().dyn_into::<WorkerGlobalScope>() {
+            worker.indexed_db()
+        } else {
+            return Err(OpenError::NoGlobalScope);
+        }
+        .map_err(OpenError::IndexedDbNotSupported)?
+        .unwrap();
+        let open_request = idb_factory
+            .open_with_u32(db_name, schema.target_version())
             .unwrap();
-        let open_request = idb_factory.open_with_u32(db_name, 1).unwrap();
 
         // Used to signal when the open request is complete.
         let (tx, rx) = oneshot::channel();
@@ -37,6 +306,7 @@ impl Database {
         open_request.set_onsuccess(Some(&on_finish.dyn_ref().unwrap()));
         open_request.set_onerror(Some(&on_finish.dyn_ref().unwrap()));
 
+        let schema = schema.clone();
         let on_upgrade_needed = Closure::once(move |event: &Event| {
             let old_version = {
                 let old_version = event
@@ -58,7 +328,7 @@ impl Database {
                 .unwrap()
                 .dyn_into::<IdbDatabase>()
                 .unwrap();
-            create_schema(&database, old_version);
+            schema.migrate(&database, old_version);
         });
         open_request.set_onupgradeneeded(Some(&on_upgrade_needed.as_ref().dyn_ref().unwrap()));
 
@@ -68,35 +338,69 @@ impl Database {
         // `result()` would return an error if the request wasn't complete yet.
         let result = open_request.result().unwrap();
         match result.dyn_into::<IdbDatabase>() {
-            Ok(db) => Ok(Database {
+            Ok(db) => Ok(IndexedDb {
                 inner: send_wrapper::SendWrapper::new(db),
             }),
             Err(err) => Err(OpenError::OpenError(err)),
         }
     }
 
-    /// Inserts the given header in the database.
-    pub async fn insert_header(&self, scale_encoded_header: &[u8]) -> Result<(), AccessError> {
-        let key = {
-            let bytes = header::hash_from_scale_encoded_header(scale_encoded_header);
-            let hex = hex::encode(&bytes);
-            JsValue::from_str(&hex)
-        };
+    /// Inserts the given header in the database. Returns [`ApplyOutcome::Applied`] if it became
+    /// the new best head, or [`ApplyOutcome::Skipped`] if it didn't (it may still have been
+    /// stored, just not as part of the canonical chain).
+    pub async fn insert_header(
+        &self,
+        scale_encoded_header: &[u8],
+    ) -> Result<ApplyOutcome, AccessError> {
+        Ok(self
+            .insert_headers(iter::once(scale_encoded_header))
+            .await?
+            .into_iter()
+            .next()
+            .unwrap())
+    }
 
-        let number = {
-            let height = header::decode(&scale_encoded_header).unwrap().number;
-            JsValue::from_f64(height as f64)
-        };
+    /// Inserts the given headers in the database, all within a single transaction. The returned
+    /// [`ApplyOutcome`]s are in the same order as `headers`.
+    ///
+    /// This is more efficient than calling [`IndexedDb::insert_header`] in a loop, as it only
+    /// involves one IndexedDB transaction rather than one per header.
+    pub async fn insert_headers<'a>(
+        &self,
+        headers: impl IntoIterator<Item = &'a [u8]>,
+    ) -> Result<Vec<ApplyOutcome>, AccessError> {
+        self.apply(headers.into_iter().map(WriteOp::InsertHeader))
+            .await
+    }
 
-        let value = {
-            let hex = hex::encode(scale_encoded_header);
-            JsValue::from_str(&hex)
-        };
+    /// Atomically applies a batch of [`WriteOp`]s within a single transaction. The returned
+    /// [`ApplyOutcome`]s are in the same order as `ops`.
+    pub async fn apply<'a>(
+        &self,
+        ops: impl IntoIterator<Item = WriteOp<'a>>,
+    ) -> Result<Vec<ApplyOutcome>, AccessError> {
+        // Collected up front rather than consumed lazily, since the transaction below must be
+        // scoped to every object store `ops` touches before any operation runs against it.
+        let ops: Vec<_> = ops.into_iter().collect();
+
+        // `"block-headers"`, `"best-chain"` and `"meta"` are always included, since
+        // `WriteOp::InsertHeader` always touches them; `WriteOp::Delete` can additionally target
+        // any object store declared through the `Schema`, so the transaction is widened to cover
+        // those too.
+        let mut columns = vec!["block-headers", "best-chain", "meta"];
+        for op in &ops {
+            if let WriteOp::Delete { column, .. } = op {
+                if !columns.contains(column) {
+                    columns.push(column);
+                }
+            }
+        }
 
         let transaction = {
             let obj_stores_list = js_sys::Array::new();
-            obj_stores_list.push(&JsValue::from_str("block-headers"));
-            obj_stores_list.push(&JsValue::from_str("best-chain"));
+            for column in &columns {
+                obj_stores_list.push(&JsValue::from_str(column));
+            }
 
             self.inner
                 .transaction_with_str_sequence_and_mode(
@@ -106,32 +410,233 @@ impl Database {
                 .unwrap()
         };
 
-        match transaction
-            .object_store("block-headers")
-            .unwrap()
-            .add_with_key(&value, &key) // Note: the order of parameters is indeed value then key
-        {
-            Ok(_) => {}
-            Err(err) => {
-                let err = err.dyn_into::<DomException>().unwrap();
-                if err.name() == "ConstraintError" {
-                    // Entry already exists in database.
-                    return Ok(());
+        let mut outcomes = Vec::new();
+
+        'ops: for op in ops {
+            match op {
+                WriteOp::InsertHeader(scale_encoded_header) => {
+                    let hash = header::hash_from_scale_encoded_header(scale_encoded_header);
+                    let decoded = header::decode(&scale_encoded_header).unwrap();
+                    let number = u64::from(decoded.number);
+                    let parent_hash = *decoded.parent_hash;
+
+                    let key = JsValue::from(Uint8Array::from(&hash[..]));
+                    let value = JsValue::from(Uint8Array::from(scale_encoded_header));
+
+                    match transaction
+                        .object_store("block-headers")
+                        .unwrap()
+                        .add_with_key(&value, &key) // Note: the order of parameters is indeed value then key
+                    {
+                        Ok(_) => {}
+                        Err(err) => {
+                            let err = err.dyn_into::<DomException>().unwrap();
+                            if err.name() == "ConstraintError" {
+                                // Entry already exists in database.
+                                outcomes.push(ApplyOutcome::Skipped);
+                                continue 'ops;
+                            }
+                            return Err(AccessError::TransactionError(err));
+                        }
+                    }
+
+                    let best_chain_store = transaction.object_store("best-chain").unwrap();
+                    let meta_store = transaction.object_store("meta").unwrap();
+
+                    let current_best = store_get(&meta_store, BEST_HEAD_KEY)
+                        .await?
+                        .map(|bytes| decode_head(&bytes))
+                        .transpose()?;
+
+                    // A header only becomes canonical if it extends or outweighs the current
+                    // best chain.
+                    if !is_new_best(current_best, number, hash) {
+                        outcomes.push(ApplyOutcome::Skipped);
+                        continue 'ops;
+                    }
+
+                    let finalized = store_get(&meta_store, FINALIZED_HEAD_KEY)
+                        .await?
+                        .map(|bytes| decode_head(&bytes))
+                        .transpose()?;
+
+                    if violates_finality(finalized, number, hash) {
+                        // Refuses to reorg below, or away from, the finalized head.
+                        outcomes.push(ApplyOutcome::Skipped);
+                        continue 'ops;
+                    }
+
+                    // Walk back from the new head along parent hashes, proving along the way
+                    // that every rewritten `best-chain` entry corresponds to a header that's
+                    // actually known, until either an already-canonical ancestor is reached (its
+                    // own ancestry having already been validated when it was written) or the walk
+                    // reaches the finalized head. The finalized head is trusted directly from
+                    // `meta` rather than requiring its header to be stored in `block-headers`,
+                    // since it may be known only as a checkpoint (e.g. right after a warp sync)
+                    // whose header was never fetched. A branch that can't be connected, or that
+                    // forks below the finalized head, is rejected outright rather than partially
+                    // rewriting `best-chain`.
+                    let mut rewrite = vec![(number, hash)];
+                    if !matches_finalized(finalized, number, hash) {
+                        let mut ancestor_hash = parent_hash;
+                        let mut ancestor_number = number;
+                        loop {
+                            let Some(n) = ancestor_number.checked_sub(1) else {
+                                break;
+                            };
+
+                            if forks_below_finality(finalized, n, ancestor_hash) {
+                                // The competing branch forks below the finalized block.
+                                outcomes.push(ApplyOutcome::Skipped);
+                                continue 'ops;
+                            }
+
+                            if matches_finalized(finalized, n, ancestor_hash) {
+                                // The finalized head itself: trusted as-is, without needing its
+                                // header to be known.
+                                rewrite.push((n, ancestor_hash));
+                                break;
+                            }
+
+                            let canonical_hash =
+                                idb_get(&best_chain_store, &JsValue::from_f64(n as f64))
+                                    .await?
+                                    .map(|bytes| <[u8; 32]>::try_from(&bytes[..]).unwrap());
+                            if canonical_hash == Some(ancestor_hash) {
+                                break;
+                            }
+
+                            let Some(ancestor_header) = store_get(
+                                &transaction.object_store("block-headers").unwrap(),
+                                &ancestor_hash,
+                            )
+                            .await?
+                            else {
+                                // The ancestor header isn't known: the branch can't be proven to
+                                // connect to the existing chain.
+                                outcomes.push(ApplyOutcome::Skipped);
+                                continue 'ops;
+                            };
+
+                            rewrite.push((n, ancestor_hash));
+                            ancestor_number = n;
+                            ancestor_hash = *header::decode(&ancestor_header).unwrap().parent_hash;
+                        }
+                    }
+
+                    for (number, hash) in rewrite {
+                        best_chain_store
+                            .put_with_key(
+                                &JsValue::from(Uint8Array::from(&hash[..])),
+                                &JsValue::from_f64(number as f64),
+                            ) // Note: the order of parameters is indeed value then key
+                            .unwrap();
+                    }
+
+                    meta_store
+                        .put_with_key(
+                            &JsValue::from(Uint8Array::from(&encode_head(number, hash)[..])),
+                            &JsValue::from(Uint8Array::from(BEST_HEAD_KEY)),
+                        ) // Note: the order of parameters is indeed value then key
+                        .unwrap();
+
+                    outcomes.push(ApplyOutcome::Applied);
+                }
+                WriteOp::Delete { column, key } => {
+                    // The `best-chain` store is keyed by plain numbers, not byte strings; every
+                    // other store uses raw bytes as-is, `block-headers` keys being 32-byte hashes.
+                    let js_key = if column == "best-chain" {
+                        match <[u8; 8]>::try_from(&key[..]) {
+                            Ok(bytes) => JsValue::from_f64(u64::from_le_bytes(bytes) as f64),
+                            Err(_) => {
+                                outcomes.push(ApplyOutcome::Skipped);
+                                continue 'ops;
+                            }
+                        }
+                    } else if column == "block-headers" && key.len() != 32 {
+                        outcomes.push(ApplyOutcome::Skipped);
+                        continue 'ops;
+                    } else {
+                        JsValue::from(Uint8Array::from(&key[..]))
+                    };
+
+                    transaction
+                        .object_store(column)
+                        .unwrap()
+                        .delete(&js_key)
+                        .unwrap();
+
+                    outcomes.push(ApplyOutcome::Applied);
                 }
-                return Err(AccessError::TransactionError(err));
             }
         }
 
-        // TODO: don't insert if not best; this needs brainstorming because of reorgs
-        transaction
-            .object_store("best-chain")
-            .unwrap()
-            .put_with_key(&key, &number) // Note: the order of parameters is indeed value then key
-            .unwrap();
-
         wait_transaction(transaction)
             .await
-            .map_err(AccessError::TransactionError)
+            .map_err(AccessError::TransactionError)?;
+        Ok(outcomes)
+    }
+
+    /// Streams the `(block number, block hash)` pairs of the best chain whose number is within
+    /// the given bounds, in increasing order.
+    pub fn iter_best_chain(
+        &self,
+        from: u64,
+        to: u64,
+    ) -> impl Stream<Item = (u64, [u8; 32])> + Unpin {
+        let (tx, rx) = mpsc::unbounded();
+
+        let transaction = self
+            .inner
+            .transaction_with_str_and_mode("best-chain", IdbTransactionMode::Readonly)
+            .unwrap();
+        let store = transaction.object_store("best-chain").unwrap();
+
+        let range =
+            IdbKeyRange::bound(&JsValue::from_f64(from as f64), &JsValue::from_f64(to as f64))
+                .unwrap();
+        let cursor_request = store
+            .open_cursor_with_range_and_direction(&range, IdbCursorDirection::Next)
+            .unwrap();
+
+        let on_error = {
+            let tx = tx.clone();
+            Closure::once_into_js(move |_: &Event| {
+                tx.close_channel();
+            })
+        };
+        cursor_request.set_onerror(Some(&on_error.dyn_ref().unwrap()));
+
+        let on_cursor = Closure::<dyn FnMut(&Event)>::new(move |event: &Event| {
+            let cursor = event
+                .target()
+                .unwrap()
+                .dyn_into::<web_sys::IdbRequest>()
+                .unwrap()
+                .result()
+                .unwrap();
+
+            let Ok(cursor) = cursor.dyn_into::<IdbCursorWithValue>() else {
+                // `result()` is `null` once the cursor has reached the end of the range.
+                tx.close_channel();
+                return;
+            };
+
+            let number = cursor.key().unwrap().as_f64().unwrap() as u64;
+            let hash = {
+                let array = cursor.value().unwrap().dyn_into::<Uint8Array>().unwrap();
+                let mut out = [0u8; 32];
+                array.copy_to(&mut out);
+                out
+            };
+
+            let _ = tx.unbounded_send((number, hash));
+            cursor.continue_().unwrap();
+        });
+        cursor_request.set_onsuccess(Some(on_cursor.as_ref().dyn_ref().unwrap()));
+        on_cursor.forget();
+
+        rx
     }
 
     /// Reads one value at the given key.
@@ -140,70 +645,760 @@ impl Database {
     ///
     /// Panics if the `column_name` is invalid.
     ///
-    async fn get(&self, column_name: &str, key: &str) -> Result<Option<String>, AccessError> {
+    async fn get(&self, column_name: &str, key: &[u8]) -> Result<Option<Vec<u8>>, AccessError> {
         let transaction = self
             .inner
             .transaction_with_str_and_mode(column_name, IdbTransactionMode::Readonly)
             .unwrap();
 
-        let store = transaction.object_store(column_name).unwrap();
-        let query = match store.get(&JsValue::from_str(key)) {
-            Ok(r) => r,
-            Err(err) => {
-                let err = err.dyn_into::<DomException>().unwrap();
-                if err.name() == "DataError" {
-                    return Ok(None);
-                }
-                panic!("Unexpected database error: {:?}")
-            }
+        store_get(&transaction.object_store(column_name).unwrap(), key).await
+    }
+
+    /// Writes a value at the given key, overwriting any previous value.
+    ///
+    /// # Panic
+    ///
+    /// Panics if the `column_name` is invalid.
+    ///
+    async fn put(&self, column_name: &str, key: &[u8], value: &[u8]) -> Result<(), AccessError> {
+        let transaction = self
+            .inner
+            .transaction_with_str_and_mode(column_name, IdbTransactionMode::Readwrite)
+            .unwrap();
+
+        transaction
+            .object_store(column_name)
+            .unwrap()
+            .put_with_key(
+                &JsValue::from(Uint8Array::from(value)),
+                &JsValue::from(Uint8Array::from(key)),
+            ) // Note: the order of parameters is indeed value then key
+            .unwrap();
+
+        wait_transaction(transaction)
+            .await
+            .map_err(AccessError::TransactionError)
+    }
+
+    /// Returns the hash and number of the current best block, if any header has been inserted.
+    pub async fn best_head(&self) -> Result<Option<(u64, [u8; 32])>, AccessError> {
+        self.get("meta", BEST_HEAD_KEY)
+            .await
+            .map(|value| value.map(|bytes| decode_head(&bytes)))?
+            .transpose()
+    }
+
+    /// Returns the hash and number of the current finalized block, if any has been set through
+    /// [`IndexedDb::set_finalized`].
+    pub async fn finalized_head(&self) -> Result<Option<(u64, [u8; 32])>, AccessError> {
+        self.get("meta", FINALIZED_HEAD_KEY)
+            .await
+            .map(|value| value.map(|bytes| decode_head(&bytes)))?
+            .transpose()
+    }
+
+    /// Sets the finalized block. The light client must never reorg the canonical chain below
+    /// this block.
+    pub async fn set_finalized(&self, number: u64, hash: [u8; 32]) -> Result<(), AccessError> {
+        let transaction = self
+            .inner
+            .transaction_with_str_and_mode("meta", IdbTransactionMode::Readwrite)
+            .unwrap();
+
+        transaction
+            .object_store("meta")
+            .unwrap()
+            .put_with_key(
+                &JsValue::from(Uint8Array::from(&encode_head(number, hash)[..])),
+                &JsValue::from(Uint8Array::from(FINALIZED_HEAD_KEY)),
+            )
+            .unwrap();
+
+        wait_transaction(transaction)
+            .await
+            .map_err(AccessError::TransactionError)
+    }
+
+    /// Deletes every header more than `keep_last` blocks behind the current best head, down to
+    /// (but not below) the finalized head.
+    pub async fn prune(&self, keep_last: u64) -> Result<(), AccessError> {
+        let Some((best_number, _)) = self.best_head().await? else {
+            return Ok(());
         };
 
+        let mut cutoff = best_number.saturating_sub(keep_last);
+        if let Some((finalized_number, _)) = self.finalized_head().await? {
+            cutoff = cutoff.min(finalized_number);
+        }
+        if cutoff == 0 {
+            return Ok(());
+        }
+
+        let transaction = {
+            let obj_stores_list = Array::new();
+            obj_stores_list.push(&JsValue::from_str("block-headers"));
+            obj_stores_list.push(&JsValue::from_str("best-chain"));
+
+            self.inner
+                .transaction_with_str_sequence_and_mode(
+                    obj_stores_list.as_ref(),
+                    IdbTransactionMode::Readwrite,
+                )
+                .unwrap()
+        };
+
+        let best_chain_store = transaction.object_store("best-chain").unwrap();
+        let block_headers_store = transaction.object_store("block-headers").unwrap();
+
+        // Bounded to be exclusive of `cutoff` itself: numbers `< cutoff` are pruned, `cutoff`
+        // and above are kept.
+        let range = IdbKeyRange::upper_bound_with_open(&JsValue::from_f64(cutoff as f64), true)
+            .unwrap();
+        let cursor_request = best_chain_store.open_cursor_with_range(&range).unwrap();
+
         let (tx, rx) = oneshot::channel();
+        let tx = std::rc::Rc::new(RefCell::new(Some(tx)));
 
-        // `once_into_js` de-allocates the closure only after it has been called. It is an
-        // error to call it multiple times, and if it is not called, it will leak.
-        // For this reason, we use the same callback on both success and failure.
-        let on_finish = Closure::once_into_js(move |_: &Event| {
-            let _ = tx.send(());
+        let on_error = {
+            let tx = std::rc::Rc::clone(&tx);
+            Closure::once_into_js(move |_: &Event| {
+                if let Some(tx) = tx.borrow_mut().take() {
+                    let _ = tx.send(());
+                }
+            })
+        };
+        cursor_request.set_onerror(Some(&on_error.dyn_ref().unwrap()));
+
+        let on_cursor = Closure::<dyn FnMut(&Event)>::new(move |event: &Event| {
+            let cursor = event
+                .target()
+                .unwrap()
+                .dyn_into::<web_sys::IdbRequest>()
+                .unwrap()
+                .result()
+                .unwrap();
+
+            let Ok(cursor) = cursor.dyn_into::<IdbCursorWithValue>() else {
+                // `result()` is `null` once the cursor has reached the end of the range.
+                if let Some(tx) = tx.borrow_mut().take() {
+                    let _ = tx.send(());
+                }
+                return;
+            };
+
+            let hash = {
+                let array = cursor.value().unwrap().dyn_into::<Uint8Array>().unwrap();
+                let mut out = [0u8; 32];
+                array.copy_to(&mut out);
+                out
+            };
+
+            block_headers_store
+                .delete(&JsValue::from(Uint8Array::from(&hash[..])))
+                .unwrap();
+            cursor.delete().unwrap();
+            cursor.continue_().unwrap();
         });
+        cursor_request.set_onsuccess(Some(on_cursor.as_ref().dyn_ref().unwrap()));
+        on_cursor.forget();
 
-        query.set_onsuccess(Some(&on_finish.dyn_ref().unwrap()));
-        query.set_onerror(Some(&on_finish.dyn_ref().unwrap()));
+        let _ = rx.await;
 
-        // Block until either `onsuccess` or `onerror` happens.
-        let _ = rx.await.unwrap();
+        wait_transaction(transaction)
+            .await
+            .map_err(AccessError::TransactionError)
+    }
 
-        if let Some(result) = query.result().unwrap().as_string() {
-            Ok(Some(result))
-        } else {
-            Err(AccessError::Corrupted(CorruptedError::UnexpectedValueTy))
+    /// Returns a strongly-typed view over the given object store, which must be part of the
+    /// [`Schema`] the database was opened with.
+    ///
+    /// Keys and values are serialized using SCALE, through the [`Encode`]/[`Decode`] traits.
+    pub fn store<K: Encode, V: Encode + Decode>(
+        &self,
+        object_store: &'static str,
+    ) -> Store<'_, K, V> {
+        Store {
+            backend: StoreBackend::IndexedDb(self),
+            object_store,
+            _marker: PhantomData,
         }
     }
 }
 
-impl Drop for Database {
+impl Storage for IndexedDb {
+    async fn insert_header(
+        &self,
+        scale_encoded_header: &[u8],
+    ) -> Result<ApplyOutcome, AccessError> {
+        IndexedDb::insert_header(self, scale_encoded_header).await
+    }
+
+    async fn insert_headers<'a>(
+        &self,
+        headers: impl IntoIterator<Item = &'a [u8]>,
+    ) -> Result<Vec<ApplyOutcome>, AccessError> {
+        IndexedDb::insert_headers(self, headers).await
+    }
+
+    async fn apply<'a>(
+        &self,
+        ops: impl IntoIterator<Item = WriteOp<'a>>,
+    ) -> Result<Vec<ApplyOutcome>, AccessError> {
+        IndexedDb::apply(self, ops).await
+    }
+
+    fn iter_best_chain(&self, from: u64, to: u64) -> impl Stream<Item = (u64, [u8; 32])> + Unpin {
+        IndexedDb::iter_best_chain(self, from, to)
+    }
+
+    async fn best_head(&self) -> Result<Option<(u64, [u8; 32])>, AccessError> {
+        IndexedDb::best_head(self).await
+    }
+
+    async fn finalized_head(&self) -> Result<Option<(u64, [u8; 32])>, AccessError> {
+        IndexedDb::finalized_head(self).await
+    }
+
+    async fn set_finalized(&self, number: u64, hash: [u8; 32]) -> Result<(), AccessError> {
+        IndexedDb::set_finalized(self, number, hash).await
+    }
+
+    async fn prune(&self, keep_last: u64) -> Result<(), AccessError> {
+        IndexedDb::prune(self, keep_last).await
+    }
+}
+
+impl Drop for IndexedDb {
     fn drop(&mut self) {
         self.inner.close();
     }
 }
 
-/// Updates a database to the latest version.
+/// Non-persistent fallback backend, used when IndexedDB is unavailable (for example inside
+/// Firefox's private mode, or in some worker contexts). Data is lost as soon as the [`Database`]
+/// is dropped.
+pub struct Memory {
+    // Guarded by a `RefCell` rather than a `Mutex`, as wasm is single-threaded; wrapped in a
+    // `SendWrapper` for the same reason `IndexedDb` wraps its `IdbDatabase`.
+    state: send_wrapper::SendWrapper<RefCell<MemoryState>>,
+}
+
+#[derive(Default)]
+struct MemoryState {
+    block_headers: BTreeMap<[u8; 32], Vec<u8>>,
+    best_chain: BTreeMap<u64, [u8; 32]>,
+    meta: BTreeMap<Vec<u8>, Vec<u8>>,
+    /// Backs object stores declared by a [`Schema`] beyond the three built-in ones above, as
+    /// accessed through [`Memory::store`]. Created on first write.
+    stores: BTreeMap<&'static str, BTreeMap<Vec<u8>, Vec<u8>>>,
+}
+
+impl Memory {
+    /// Creates a new, empty in-memory database.
+    pub fn new() -> Self {
+        Memory {
+            state: send_wrapper::SendWrapper::new(RefCell::new(MemoryState::default())),
+        }
+    }
+
+    /// Inserts the given header in the database. Returns [`ApplyOutcome::Applied`] if it became
+    /// the new best head, or [`ApplyOutcome::Skipped`] if it didn't (it may still have been
+    /// stored, just not as part of the canonical chain).
+    pub async fn insert_header(
+        &self,
+        scale_encoded_header: &[u8],
+    ) -> Result<ApplyOutcome, AccessError> {
+        Ok(self
+            .insert_headers(iter::once(scale_encoded_header))
+            .await?
+            .into_iter()
+            .next()
+            .unwrap())
+    }
+
+    /// Inserts the given headers in the database, all within a single atomic operation. The
+    /// returned [`ApplyOutcome`]s are in the same order as `headers`.
+    pub async fn insert_headers<'a>(
+        &self,
+        headers: impl IntoIterator<Item = &'a [u8]>,
+    ) -> Result<Vec<ApplyOutcome>, AccessError> {
+        self.apply(headers.into_iter().map(WriteOp::InsertHeader))
+            .await
+    }
+
+    /// Atomically applies a batch of [`WriteOp`]s. The returned [`ApplyOutcome`]s are in the same
+    /// order as `ops`.
+    pub async fn apply<'a>(
+        &self,
+        ops: impl IntoIterator<Item = WriteOp<'a>>,
+    ) -> Result<Vec<ApplyOutcome>, AccessError> {
+        let mut state = self.state.borrow_mut();
+        let mut outcomes = Vec::new();
+
+        'ops: for op in ops {
+            match op {
+                WriteOp::InsertHeader(scale_encoded_header) => {
+                    let hash = header::hash_from_scale_encoded_header(scale_encoded_header);
+                    if state.block_headers.contains_key(&hash) {
+                        // Entry already exists in database.
+                        outcomes.push(ApplyOutcome::Skipped);
+                        continue 'ops;
+                    }
+
+                    let decoded = header::decode(&scale_encoded_header).unwrap();
+                    let number = u64::from(decoded.number);
+                    let parent_hash = *decoded.parent_hash;
+
+                    state
+                        .block_headers
+                        .insert(hash, scale_encoded_header.to_vec());
+
+                    let current_best = state
+                        .meta
+                        .get(BEST_HEAD_KEY)
+                        .map(|bytes| decode_head(bytes))
+                        .transpose()?;
+
+                    if !is_new_best(current_best, number, hash) {
+                        outcomes.push(ApplyOutcome::Skipped);
+                        continue 'ops;
+                    }
+
+                    let finalized = state
+                        .meta
+                        .get(FINALIZED_HEAD_KEY)
+                        .map(|bytes| decode_head(bytes))
+                        .transpose()?;
+
+                    if violates_finality(finalized, number, hash) {
+                        // Refuses to reorg below, or away from, the finalized head.
+                        outcomes.push(ApplyOutcome::Skipped);
+                        continue 'ops;
+                    }
+
+                    // See the matching comment in `IndexedDb::apply`.
+                    let mut rewrite = vec![(number, hash)];
+                    if !matches_finalized(finalized, number, hash) {
+                        let mut ancestor_hash = parent_hash;
+                        let mut ancestor_number = number;
+                        loop {
+                            let Some(n) = ancestor_number.checked_sub(1) else {
+                                break;
+                            };
+
+                            if forks_below_finality(finalized, n, ancestor_hash) {
+                                // The competing branch forks below the finalized block.
+                                outcomes.push(ApplyOutcome::Skipped);
+                                continue 'ops;
+                            }
+
+                            if matches_finalized(finalized, n, ancestor_hash) {
+                                // The finalized head itself: trusted as-is, without needing its
+                                // header to be known.
+                                rewrite.push((n, ancestor_hash));
+                                break;
+                            }
+
+                            if state.best_chain.get(&n) == Some(&ancestor_hash) {
+                                break;
+                            }
+
+                            let Some(ancestor_header) = state.block_headers.get(&ancestor_hash)
+                            else {
+                                // The ancestor header isn't known: the branch can't be proven to
+                                // connect to the existing chain.
+                                outcomes.push(ApplyOutcome::Skipped);
+                                continue 'ops;
+                            };
+
+                            rewrite.push((n, ancestor_hash));
+                            ancestor_number = n;
+                            ancestor_hash = *header::decode(ancestor_header).unwrap().parent_hash;
+                        }
+                    }
+
+                    for (number, hash) in rewrite {
+                        state.best_chain.insert(number, hash);
+                    }
+
+                    state
+                        .meta
+                        .insert(BEST_HEAD_KEY.to_vec(), encode_head(number, hash).to_vec());
+
+                    outcomes.push(ApplyOutcome::Applied);
+                }
+                WriteOp::Delete { column, key } => {
+                    let applied = match column {
+                        "block-headers" => match <[u8; 32]>::try_from(&key[..]) {
+                            Ok(key) => {
+                                state.block_headers.remove(&key);
+                                true
+                            }
+                            Err(_) => false,
+                        },
+                        "meta" => {
+                            state.meta.remove(&key);
+                            true
+                        }
+                        "best-chain" => match <[u8; 8]>::try_from(&key[..]) {
+                            Ok(key) => {
+                                state.best_chain.remove(&u64::from_le_bytes(key));
+                                true
+                            }
+                            Err(_) => false,
+                        },
+                        _ => match state.stores.get_mut(column) {
+                            // Any other store declared through the `Schema`, as accessed through
+                            // `Memory::store`. `stores` entries are only created on first write,
+                            // so a delete against a never-written-to store is a no-op.
+                            Some(store) => {
+                                store.remove(&key);
+                                true
+                            }
+                            None => true,
+                        },
+                    };
+                    outcomes.push(if applied {
+                        ApplyOutcome::Applied
+                    } else {
+                        ApplyOutcome::Skipped
+                    });
+                }
+            }
+        }
+
+        Ok(outcomes)
+    }
+
+    /// Streams the `(block number, block hash)` pairs of the best chain whose number is within
+    /// the given bounds, in increasing order.
+    pub fn iter_best_chain(
+        &self,
+        from: u64,
+        to: u64,
+    ) -> impl Stream<Item = (u64, [u8; 32])> + Unpin {
+        let items: Vec<_> = self
+            .state
+            .borrow()
+            .best_chain
+            .range(from..=to)
+            .map(|(&number, &hash)| (number, hash))
+            .collect();
+        stream::iter(items)
+    }
+
+    /// Returns the hash and number of the current best block, if any header has been inserted.
+    pub async fn best_head(&self) -> Result<Option<(u64, [u8; 32])>, AccessError> {
+        self.state
+            .borrow()
+            .meta
+            .get(BEST_HEAD_KEY)
+            .map(|bytes| decode_head(bytes))
+            .transpose()
+    }
+
+    /// Returns the hash and number of the current finalized block, if any has been set through
+    /// [`Memory::set_finalized`].
+    pub async fn finalized_head(&self) -> Result<Option<(u64, [u8; 32])>, AccessError> {
+        self.state
+            .borrow()
+            .meta
+            .get(FINALIZED_HEAD_KEY)
+            .map(|bytes| decode_head(bytes))
+            .transpose()
+    }
+
+    /// Sets the finalized block. The light client must never reorg the canonical chain below
+    /// this block.
+    pub async fn set_finalized(&self, number: u64, hash: [u8; 32]) -> Result<(), AccessError> {
+        self.state
+            .borrow_mut()
+            .meta
+            .insert(FINALIZED_HEAD_KEY.to_vec(), encode_head(number, hash).to_vec());
+        Ok(())
+    }
+
+    /// Deletes every header more than `keep_last` blocks behind the current best head, down to
+    /// (but not below) the finalized head.
+    pub async fn prune(&self, keep_last: u64) -> Result<(), AccessError> {
+        let mut state = self.state.borrow_mut();
+
+        let Some(&best_number) = state.best_chain.keys().next_back() else {
+            return Ok(());
+        };
+
+        let mut cutoff = best_number.saturating_sub(keep_last);
+        if let Some(bytes) = state.meta.get(FINALIZED_HEAD_KEY) {
+            let (finalized_number, _) = decode_head(bytes)?;
+            cutoff = cutoff.min(finalized_number);
+        }
+        if cutoff == 0 {
+            return Ok(());
+        }
+
+        let stale: Vec<u64> = state.best_chain.range(..cutoff).map(|(&n, _)| n).collect();
+        for number in stale {
+            if let Some(hash) = state.best_chain.remove(&number) {
+                state.block_headers.remove(&hash);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reads one value at the given key, from an object store declared through [`Memory::store`].
+    fn get(&self, object_store: &'static str, key: &[u8]) -> Option<Vec<u8>> {
+        self.state
+            .borrow()
+            .stores
+            .get(object_store)
+            .and_then(|store| store.get(key))
+            .cloned()
+    }
+
+    /// Writes a value at the given key, overwriting any previous value, in an object store
+    /// declared through [`Memory::store`].
+    fn put(&self, object_store: &'static str, key: Vec<u8>, value: Vec<u8>) {
+        self.state
+            .borrow_mut()
+            .stores
+            .entry(object_store)
+            .or_default()
+            .insert(key, value);
+    }
+
+    /// Returns a strongly-typed view over the given object store, which must be part of the
+    /// [`Schema`] the database was opened with.
+    ///
+    /// Keys and values are serialized using SCALE, through the [`Encode`]/[`Decode`] traits.
+    pub fn store<K: Encode, V: Encode + Decode>(
+        &self,
+        object_store: &'static str,
+    ) -> Store<'_, K, V> {
+        Store {
+            backend: StoreBackend::Memory(self),
+            object_store,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl Default for Memory {
+    fn default() -> Self {
+        Memory::new()
+    }
+}
+
+impl Storage for Memory {
+    async fn insert_header(
+        &self,
+        scale_encoded_header: &[u8],
+    ) -> Result<ApplyOutcome, AccessError> {
+        Memory::insert_header(self, scale_encoded_header).await
+    }
+
+    async fn insert_headers<'a>(
+        &self,
+        headers: impl IntoIterator<Item = &'a [u8]>,
+    ) -> Result<Vec<ApplyOutcome>, AccessError> {
+        Memory::insert_headers(self, headers).await
+    }
+
+    async fn apply<'a>(
+        &self,
+        ops: impl IntoIterator<Item = WriteOp<'a>>,
+    ) -> Result<Vec<ApplyOutcome>, AccessError> {
+        Memory::apply(self, ops).await
+    }
+
+    fn iter_best_chain(&self, from: u64, to: u64) -> impl Stream<Item = (u64, [u8; 32])> + Unpin {
+        Memory::iter_best_chain(self, from, to)
+    }
+
+    async fn best_head(&self) -> Result<Option<(u64, [u8; 32])>, AccessError> {
+        Memory::best_head(self).await
+    }
+
+    async fn finalized_head(&self) -> Result<Option<(u64, [u8; 32])>, AccessError> {
+        Memory::finalized_head(self).await
+    }
+
+    async fn set_finalized(&self, number: u64, hash: [u8; 32]) -> Result<(), AccessError> {
+        Memory::set_finalized(self, number, hash).await
+    }
+
+    async fn prune(&self, keep_last: u64) -> Result<(), AccessError> {
+        Memory::prune(self, keep_last).await
+    }
+}
+
+/// A strongly-typed view over a single object store, obtained through [`Database::store`] (or,
+/// to tie it to one specific backend, [`IndexedDb::store`]/[`Memory::store`]).
+///
+/// Hides the `JsValue`/`DomException` plumbing behind plain [`Store::get`]/[`Store::put`]
+/// methods, serializing keys and values with SCALE. Works identically regardless of which
+/// [`BackendKind`] it's backed by.
+pub struct Store<'db, K, V> {
+    backend: StoreBackend<'db>,
+    object_store: &'static str,
+    _marker: PhantomData<fn() -> (K, V)>,
+}
+
+#[derive(Clone, Copy)]
+enum StoreBackend<'db> {
+    IndexedDb(&'db IndexedDb),
+    Memory(&'db Memory),
+}
+
+impl<'db, K: Encode, V: Encode + Decode> Store<'db, K, V> {
+    /// Reads the value associated with the given key, if any.
+    pub async fn get(&self, key: &K) -> Result<Option<V>, AccessError> {
+        let bytes = match self.backend {
+            StoreBackend::IndexedDb(db) => db.get(self.object_store, &key.encode()).await?,
+            StoreBackend::Memory(db) => db.get(self.object_store, &key.encode()),
+        };
+        let Some(bytes) = bytes else {
+            return Ok(None);
+        };
+        V::decode(&mut &bytes[..])
+            .map(Some)
+            .map_err(|_| AccessError::Corrupted(CorruptedError::UnexpectedValueTy))
+    }
+
+    /// Writes the given key/value pair, overwriting any previous value at that key.
+    pub async fn put(&self, key: &K, value: &V) -> Result<(), AccessError> {
+        match self.backend {
+            StoreBackend::IndexedDb(db) => {
+                db.put(self.object_store, &key.encode(), &value.encode())
+                    .await
+            }
+            StoreBackend::Memory(db) => {
+                db.put(self.object_store, key.encode(), value.encode());
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Describes the object stores a database must contain, grouped by the schema version that
+/// introduces them, and knows how to migrate a database between versions.
 ///
-/// Called by the `onupgradeneeded` handle of the database.
-fn create_schema(database: &IdbDatabase, old_version: u32) {
-    if old_version <= 0 {
-        // Keys are hex-encoded block hashes, and values are hex-encoded SCALE-encoded block
-        // headers.
-        database.create_object_store("block-headers").unwrap();
+/// ```ignore
+/// Schema::new()
+///     .version(["block-headers", "best-chain"])
+///     .version(["meta"])
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Schema {
+    versions: Vec<Vec<&'static str>>,
+}
 
-        // Keys are block numbers, and values are hex-encoded block hashes.
-        database.create_object_store("best-chain").unwrap();
+impl Schema {
+    /// Creates an empty schema. Add versions to it with [`Schema::version`].
+    pub fn new() -> Self {
+        Schema::default()
     }
 
-    // Note: add new versions with something like:
-    // if current_version <= N {
-    //     database.create_object_store("...").unwrap();
-    // }
+    /// Appends a new schema version, which creates the given object stores when a database is
+    /// migrated to it. Versions are numbered in the order in which they are added, starting at 1.
+    pub fn version(mut self, object_stores: impl IntoIterator<Item = &'static str>) -> Self {
+        self.versions.push(object_stores.into_iter().collect());
+        self
+    }
+
+    /// The schema version that [`IndexedDb::open`] should request.
+    fn target_version(&self) -> u32 {
+        self.versions.len() as u32
+    }
+
+    /// Creates the object stores of every version above `old_version`, in order.
+    ///
+    /// Called by the `onupgradeneeded` handler of the database.
+    fn migrate(&self, database: &IdbDatabase, old_version: u32) {
+        for (zero_based_version, object_stores) in self.versions.iter().enumerate() {
+            let version = zero_based_version as u32 + 1;
+            if version > old_version {
+                for object_store in object_stores {
+                    database.create_object_store(object_store).unwrap();
+                }
+            }
+        }
+    }
+
+    /// The schema declaring the object stores that [`Database`] itself reads and writes for
+    /// headers and canonical-chain tracking. [`Database::open`] doesn't apply it on its own: the
+    /// schema passed to it must contain these stores.
+    pub fn light_client() -> Self {
+        Schema::new()
+            .version(["block-headers", "best-chain"])
+            .version(["meta"])
+    }
+}
+
+/// Key, within the `meta` object store, of the best block head.
+const BEST_HEAD_KEY: &[u8] = b"best_head";
+/// Key, within the `meta` object store, of the finalized block head.
+const FINALIZED_HEAD_KEY: &[u8] = b"finalized_head";
+
+/// Encodes a `(number, hash)` pair as stored in the `meta` object store.
+fn encode_head(number: u64, hash: [u8; 32]) -> [u8; 40] {
+    let mut out = [0u8; 40];
+    out[..8].copy_from_slice(&number.to_le_bytes());
+    out[8..].copy_from_slice(&hash);
+    out
+}
+
+/// Inverse of [`encode_head`].
+fn decode_head(encoded: &[u8]) -> Result<(u64, [u8; 32]), AccessError> {
+    let encoded: &[u8; 40] = encoded
+        .try_into()
+        .map_err(|_| AccessError::Corrupted(CorruptedError::UnexpectedValueTy))?;
+    let number = u64::from_le_bytes(encoded[..8].try_into().unwrap());
+    let hash = encoded[8..].try_into().unwrap();
+    Ok((number, hash))
+}
+
+/// Whether a header at `(number, hash)` should become the new best head given the current one.
+/// Shared by `IndexedDb::apply` and `Memory::apply`.
+///
+/// Ties are broken deterministically by comparing hashes, so that all nodes converge on the same
+/// chain without needing a fork choice rule based on externally-observed weight.
+fn is_new_best(current_best: Option<(u64, [u8; 32])>, number: u64, hash: [u8; 32]) -> bool {
+    match current_best {
+        None => true,
+        Some((best_number, best_hash)) => {
+            number > best_number || (number == best_number && hash < best_hash)
+        }
+    }
+}
+
+/// Whether accepting `(number, hash)` as the new best head, as-is, would reorg below or away
+/// from the finalized head. Shared by `IndexedDb::apply` and `Memory::apply`.
+fn violates_finality(finalized: Option<(u64, [u8; 32])>, number: u64, hash: [u8; 32]) -> bool {
+    match finalized {
+        Some((finalized_number, finalized_hash)) => {
+            number < finalized_number || (number == finalized_number && hash != finalized_hash)
+        }
+        None => false,
+    }
+}
+
+/// While walking a candidate branch back to height `n`, whether `hash` diverges from the
+/// finalized head at that height, meaning the branch forks below finality. Shared by
+/// `IndexedDb::apply` and `Memory::apply`.
+fn forks_below_finality(finalized: Option<(u64, [u8; 32])>, n: u64, hash: [u8; 32]) -> bool {
+    matches!(
+        finalized,
+        Some((finalized_number, finalized_hash)) if n == finalized_number && hash != finalized_hash
+    )
+}
+
+/// Whether `(n, hash)` is exactly the finalized head. While walking a candidate branch back,
+/// reaching this confirms the branch connects to the trusted checkpoint without needing the
+/// finalized head's own header to be known (it may never have been fetched, e.g. right after a
+/// warp sync). Shared by `IndexedDb::apply` and `Memory::apply`.
+fn matches_finalized(finalized: Option<(u64, [u8; 32])>, n: u64, hash: [u8; 32]) -> bool {
+    finalized == Some((n, hash))
 }
 
 /// Waits for the given transaction to complete.
@@ -226,10 +1421,67 @@ async fn wait_transaction(transaction: IdbTransaction) -> Result<(), DomExceptio
     }
 }
 
+/// Performs a `get` request against the given object store and awaits its result.
+async fn idb_get(
+    store: &web_sys::IdbObjectStore,
+    key: &JsValue,
+) -> Result<Option<Vec<u8>>, AccessError> {
+    let query = match store.get(key) {
+        Ok(r) => r,
+        Err(err) => {
+            let err = err.dyn_into::<DomException>().unwrap();
+            if err.name() == "DataError" {
+                return Ok(None);
+            }
+            panic!("Unexpected database error: {:?}", err)
+        }
+    };
+
+    let (tx, rx) = oneshot::channel();
+
+    // `once_into_js` de-allocates the closure only after it has been called. It is an
+    // error to call it multiple times, and if it is not called, it will leak.
+    // For this reason, we use the same callback on both success and failure.
+    let on_finish = Closure::once_into_js(move |_: &Event| {
+        let _ = tx.send(());
+    });
+
+    query.set_onsuccess(Some(&on_finish.dyn_ref().unwrap()));
+    query.set_onerror(Some(&on_finish.dyn_ref().unwrap()));
+
+    // Block until either `onsuccess` or `onerror` happens.
+    let _ = rx.await.unwrap();
+
+    let result = query.result().unwrap();
+    if result.is_undefined() {
+        return Ok(None);
+    }
+
+    // Values are written as `Uint8Array`s, which IndexedDB hands back as `Uint8Array`s.
+    if let Some(array) = result.dyn_ref::<Uint8Array>() {
+        return Ok(Some(array.to_vec()));
+    }
+
+    match result.dyn_into::<ArrayBuffer>() {
+        Ok(buffer) => Ok(Some(Uint8Array::new(&buffer).to_vec())),
+        Err(_) => Err(AccessError::Corrupted(CorruptedError::UnexpectedValueTy)),
+    }
+}
+
+/// Like [`idb_get`], but for object stores whose keys are raw bytes rather than numbers.
+async fn store_get(
+    store: &web_sys::IdbObjectStore,
+    key: &[u8],
+) -> Result<Option<Vec<u8>>, AccessError> {
+    idb_get(store, &JsValue::from(Uint8Array::from(key))).await
+}
+
 /// Error when opening the database.
 #[derive(Debug, derive_more::Display)]
 pub enum OpenError {
-    NoWindow,
+    /// Neither a `Window` nor a `WorkerGlobalScope` is available, so there is no global to open
+    /// an `IDBFactory` from.
+    NoGlobalScope,
     /// IndexedDB is not supported by the environment.
     #[display(fmt = "IndexedDB is not supported by the environment: {:?}", _0)]
     IndexedDbNotSupported(JsValue),
@@ -250,3 +1502,94 @@ pub enum AccessError {
 pub enum CorruptedError {
     UnexpectedValueTy,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn is_new_best_prefers_higher_block() {
+        assert!(is_new_best(Some((5, [1; 32])), 6, [0; 32]));
+        assert!(!is_new_best(Some((5, [1; 32])), 4, [2; 32]));
+    }
+
+    #[test]
+    fn is_new_best_breaks_ties_on_lower_hash() {
+        assert!(is_new_best(Some((5, [2; 32])), 5, [1; 32]));
+        assert!(!is_new_best(Some((5, [1; 32])), 5, [2; 32]));
+    }
+
+    #[test]
+    fn is_new_best_accepts_anything_when_chain_is_empty() {
+        assert!(is_new_best(None, 0, [0; 32]));
+    }
+
+    #[test]
+    fn violates_finality_rejects_below_finalized_height() {
+        let finalized = Some((10, [9; 32]));
+        assert!(violates_finality(finalized, 9, [0; 32]));
+    }
+
+    #[test]
+    fn violates_finality_rejects_different_hash_at_finalized_height() {
+        let finalized = Some((10, [9; 32]));
+        assert!(violates_finality(finalized, 10, [8; 32]));
+        assert!(!violates_finality(finalized, 10, [9; 32]));
+    }
+
+    #[test]
+    fn violates_finality_allows_anything_above_finalized_height() {
+        // The exact-height check alone isn't enough to accept a header above finality; that's
+        // what `forks_below_finality` is for once its branch is walked back.
+        let finalized = Some((10, [9; 32]));
+        assert!(!violates_finality(finalized, 11, [0; 32]));
+    }
+
+    #[test]
+    fn violates_finality_is_never_true_without_a_finalized_head() {
+        assert!(!violates_finality(None, 0, [0; 32]));
+    }
+
+    #[test]
+    fn forks_below_finality_catches_branch_diverging_at_finalized_height() {
+        // A competing, equal-or-higher-height branch that wins the best-head tie-break must
+        // still be rejected once the walk-back reaches the finalized height on a different hash.
+        let finalized = Some((10, [9; 32]));
+        assert!(forks_below_finality(finalized, 10, [1; 32]));
+        assert!(!forks_below_finality(finalized, 10, [9; 32]));
+        assert!(!forks_below_finality(finalized, 11, [1; 32]));
+    }
+
+    #[test]
+    fn forks_below_finality_is_never_true_without_a_finalized_head() {
+        assert!(!forks_below_finality(None, 10, [1; 32]));
+    }
+
+    #[test]
+    fn matches_finalized_accepts_the_finalized_head_alone() {
+        let finalized = Some((10, [9; 32]));
+        assert!(matches_finalized(finalized, 10, [9; 32]));
+        assert!(!matches_finalized(finalized, 10, [1; 32]));
+        assert!(!matches_finalized(finalized, 9, [9; 32]));
+    }
+
+    #[test]
+    fn matches_finalized_is_never_true_without_a_finalized_head() {
+        assert!(!matches_finalized(None, 10, [9; 32]));
+    }
+
+    #[test]
+    fn walk_back_can_reach_the_finalized_head_without_its_header_being_known() {
+        // Regression test for the checkpoint/warp-sync bootstrap bug: once the walk-back
+        // reaches the finalized height, the finalized head must be accepted on `meta` alone,
+        // before (and instead of) requiring its header to be looked up in `block-headers`.
+        // `forks_below_finality`/`matches_finalized` are exactly the two checks `apply()` runs,
+        // in that order, at each walked-back height; this exercises them the same way.
+        let finalized = Some((10, [9; 32]));
+        let n = 10;
+        let ancestor_hash = [9; 32];
+
+        assert!(!forks_below_finality(finalized, n, ancestor_hash));
+        assert!(matches_finalized(finalized, n, ancestor_hash));
+    }
+}